@@ -1,31 +1,94 @@
 //! Block Cache Layer
 //! Implements about the disk block cache functionality
 use super::{BlockDevice, BLOCK_SZ};
+use alloc::collections::BTreeMap;
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use lazy_static::*;
 use spin::Mutex;
+
+/// A dirty block is flushed automatically by `on_timer_tick` once it has
+/// stayed dirty for at least this many ticks.
+const DIRTY_FLUSH_THRESHOLD: usize = 100;
+
+/// Placeholder block index `BLOCK_CACHE_MANAGER` is constructed with before
+/// anyone has told it the real filesystem layout. Deliberately implausible
+/// (no real device has this many blocks) so that using the manager without
+/// first calling [`init_checksum_region_start`] trips the `debug_assert` in
+/// `get_block_cache` instead of silently colliding with the data region.
+const UNINIT_CHECKSUM_REGION_START: usize = usize::MAX;
+/// Number of `u32` checksum slots that fit in one disk block.
+const CHECKSUMS_PER_BLOCK: usize = BLOCK_SZ / core::mem::size_of::<u32>();
+
+/// Compute a CRC32 (IEEE 802.3 polynomial) checksum over `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// The disk block and byte offset holding `block_id`'s persisted checksum,
+/// given that the checksum region starts at `checksum_region_start`.
+fn checksum_location(block_id: usize, checksum_region_start: usize) -> (usize, usize) {
+    debug_assert!(
+        block_id < checksum_region_start,
+        "block {} collides with the checksum region starting at {}; \
+         checksum_region_start must be sized from the real fs layout",
+        block_id,
+        checksum_region_start
+    );
+    let checksum_block = checksum_region_start + block_id / CHECKSUMS_PER_BLOCK;
+    let offset = (block_id % CHECKSUMS_PER_BLOCK) * core::mem::size_of::<u32>();
+    (checksum_block, offset)
+}
+
 /// BlockCache is a cache for a block in disk.
 pub struct BlockCache {
     cache: Vec<u8>,
     block_id: usize,
     block_device: Arc<dyn BlockDevice>,
     modified: bool,
+    /// CRC32 of `cache` as of the last time it was (re)computed.
+    checksum: u32,
+    /// Set by `get_mut` whenever the buffer may have changed; tells `sync`
+    /// it must recompute `checksum` before persisting it. Kept separate from
+    /// `modified` so the (more expensive) checksum recompute stays lazy
+    /// instead of running on every access.
+    checksum_dirty: bool,
+    /// Block index the checksum region starts at on this device; see
+    /// `checksum_location`.
+    checksum_region_start: usize,
 }
 
 impl BlockCache {
-    /// Load a new BlockCache from disk.
-    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
+    /// Load a new BlockCache from disk. `checksum_region_start` must be
+    /// sized so the checksum region it points at cannot overlap this block
+    /// (or any other block the filesystem actually uses).
+    pub fn new(
+        block_id: usize,
+        block_device: Arc<dyn BlockDevice>,
+        checksum_region_start: usize,
+    ) -> Self {
         // for alignment and move effciency
         let mut cache = vec![0u8; BLOCK_SZ];
         block_device.read_block(block_id, &mut cache);
+        let checksum = crc32(&cache);
         Self {
             cache,
             block_id,
             block_device,
             modified: false,
+            checksum,
+            checksum_dirty: false,
+            checksum_region_start,
         }
     }
     /// Get the slice in the block cache according to the offset.
@@ -50,6 +113,8 @@ impl BlockCache {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
         self.modified = true;
+        self.checksum_dirty = true;
+        BLOCK_CACHE_MANAGER.lock().mark_dirty(self.block_id);
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -67,6 +132,38 @@ impl BlockCache {
             self.modified = false;
             self.block_device.write_block(self.block_id, &self.cache);
         }
+        if self.checksum_dirty {
+            self.checksum_dirty = false;
+            self.checksum = crc32(&self.cache);
+            self.persist_checksum();
+        }
+    }
+    /// Write `self.checksum` into the device's checksum region.
+    fn persist_checksum(&self) {
+        let (checksum_block, offset) = checksum_location(self.block_id, self.checksum_region_start);
+        let mut region = vec![0u8; BLOCK_SZ];
+        self.block_device.read_block(checksum_block, &mut region);
+        region[offset..offset + 4].copy_from_slice(&self.checksum.to_le_bytes());
+        self.block_device.write_block(checksum_block, &region);
+    }
+    /// Compare this block's last-known-good checksum against the value
+    /// persisted in the device's checksum region, catching a block whose
+    /// contents as read from the device (or whose persisted checksum) no
+    /// longer match. The cached buffer itself is not rehashed here.
+    ///
+    /// A block with a write pending (`checksum_dirty`) hasn't had its new
+    /// checksum persisted yet, so there is nothing meaningful to compare
+    /// against until it is synced; such a block is reported as fine rather
+    /// than flagged as corrupt.
+    pub fn verify(&self) -> bool {
+        if self.checksum_dirty {
+            return true;
+        }
+        let (checksum_block, offset) = checksum_location(self.block_id, self.checksum_region_start);
+        let mut region = vec![0u8; BLOCK_SZ];
+        self.block_device.read_block(checksum_block, &mut region);
+        let stored = u32::from_le_bytes(region[offset..offset + 4].try_into().unwrap());
+        stored == self.checksum
     }
 }
 
@@ -76,59 +173,242 @@ impl Drop for BlockCache {
     }
 }
 
+/// Default number of blocks a `BlockCacheManager` holds before it starts
+/// evicting, if no other capacity is requested.
 const BLOCK_CACHE_SIZE: usize = 16;
 
+/// One entry tracked by `BlockCacheManager`.
+struct CacheEntry {
+    block_id: usize,
+    cache: Arc<Mutex<BlockCache>>,
+    /// Clock/second-chance reference bit: set whenever `get_block_cache` hits
+    /// this entry, cleared by the eviction hand as it sweeps past.
+    referenced: bool,
+}
+
 /// BlockCacheManager is a manager for BlockCache.
 pub struct BlockCacheManager {
-    /// (block_id, block_cache)
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    queue: VecDeque<CacheEntry>,
+    /// Maximum number of blocks kept cached before eviction kicks in. May
+    /// grow past this if every cached block is pinned when a new one is
+    /// needed.
+    capacity: usize,
+    /// Position of the clock's sweeping hand into `queue`.
+    hand: usize,
+    /// Dirty blocks not yet synced, mapped to the tick at which they became
+    /// dirty so `on_timer_tick` can age them out.
+    dirty: BTreeMap<usize, usize>,
+    /// Ticks elapsed since this manager was created, advanced by
+    /// `on_timer_tick`.
+    tick: usize,
+    /// Block index the checksum region starts at; forwarded to every
+    /// `BlockCache` this manager creates. Must be sized by the caller from
+    /// the real filesystem layout so it cannot overlap the data region.
+    checksum_region_start: usize,
+    /// Set by [`BlockCacheManager::set_checksum_region_start`] once
+    /// `checksum_region_start` has actually been sized from a real layout,
+    /// rather than left at its implausible construction-time placeholder.
+    checksum_region_initialized: bool,
 }
 
 impl BlockCacheManager {
-    /// Create a new BlockCacheManager with an empty queue (block_id, block_cache)
-    pub fn new() -> Self {
+    /// Create a new, empty BlockCacheManager that holds at most `capacity`
+    /// blocks before evicting, reserving the checksum region starting at
+    /// block `checksum_region_start` (which must lie past every block the
+    /// filesystem actually uses).
+    pub fn new(capacity: usize, checksum_region_start: usize) -> Self {
         Self {
             queue: VecDeque::new(),
+            capacity,
+            hand: 0,
+            dirty: BTreeMap::new(),
+            tick: 0,
+            checksum_region_start,
+            checksum_region_initialized: true,
         }
     }
+    /// Size `checksum_region_start` from the real filesystem layout. Must be
+    /// called before this manager caches a single block (i.e. before
+    /// `get_block_cache`'s first call) — every `BlockCache` it has already
+    /// created keeps the `checksum_region_start` it was built with, so
+    /// changing it afterwards would leave already-cached blocks checksummed
+    /// against a region their own checksum was never actually written into.
+    pub fn set_checksum_region_start(&mut self, checksum_region_start: usize) {
+        debug_assert!(
+            self.queue.is_empty(),
+            "checksum_region_start must be sized before any block is cached"
+        );
+        self.checksum_region_start = checksum_region_start;
+        self.checksum_region_initialized = true;
+    }
+    /// Record that `block_id` has an unsynced write, called from
+    /// `BlockCache::get_mut`.
+    pub fn mark_dirty(&mut self, block_id: usize) {
+        self.dirty.entry(block_id).or_insert(self.tick);
+    }
+    /// Drop `block_id` from the dirty list and return its cache handle (if
+    /// still cached), without syncing it.
+    ///
+    /// Deliberately returns the `Arc` instead of syncing here: syncing takes
+    /// the block's own lock, and this method runs with the manager's lock
+    /// already held by the caller. `BlockCache::get_mut` takes the opposite
+    /// order — a block's lock first, then (via `mark_dirty`) the manager's —
+    /// so actually calling `.sync()` from inside here would be a lock-order
+    /// inversion against it. Callers sync the returned handle themselves
+    /// only after releasing the manager's lock; see the module-level
+    /// `flush_dirty`/`sync_block`/`on_timer_tick`.
+    fn take_dirty_cache(&mut self, block_id: usize) -> Option<Arc<Mutex<BlockCache>>> {
+        self.dirty.remove(&block_id);
+        self.queue
+            .iter()
+            .find(|entry| entry.block_id == block_id)
+            .map(|entry| Arc::clone(&entry.cache))
+    }
+    /// Drop every currently-dirty block from the dirty list and return their
+    /// cache handles, for the same lock-ordering reason as
+    /// `take_dirty_cache`.
+    fn take_all_dirty_caches(&mut self) -> Vec<Arc<Mutex<BlockCache>>> {
+        let block_ids: Vec<usize> = self.dirty.keys().copied().collect();
+        self.dirty.clear();
+        block_ids
+            .into_iter()
+            .filter_map(|block_id| {
+                self.queue
+                    .iter()
+                    .find(|entry| entry.block_id == block_id)
+                    .map(|entry| Arc::clone(&entry.cache))
+            })
+            .collect()
+    }
+    /// Advance the manager's internal clock by one tick, drop every block
+    /// that has been dirty for at least `DIRTY_FLUSH_THRESHOLD` ticks from
+    /// the dirty list, and return their cache handles (unsynced, for the
+    /// same lock-ordering reason as `take_dirty_cache`).
+    ///
+    /// This only bounds the crash window once something actually calls it
+    /// once per timer interrupt; wiring that call into the interrupt
+    /// handler belongs in the kernel's trap code (`os/src/trap`), which
+    /// isn't part of this crate and isn't touched here.
+    fn take_stale_dirty_caches(&mut self) -> Vec<Arc<Mutex<BlockCache>>> {
+        self.tick += 1;
+        let stale: Vec<usize> = self
+            .dirty
+            .iter()
+            .filter(|&(_, &since)| self.tick.wrapping_sub(since) >= DIRTY_FLUSH_THRESHOLD)
+            .map(|(&block_id, _)| block_id)
+            .collect();
+        stale
+            .into_iter()
+            .filter_map(|block_id| self.take_dirty_cache(block_id))
+            .collect()
+    }
     /// Get a block cache from the queue. according to the block_id.
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
+        debug_assert!(
+            self.checksum_region_initialized,
+            "checksum_region_start was never sized from the real fs layout; \
+             call block_cache::init_checksum_region_start first"
+        );
+        if let Some(entry) = self.queue.iter_mut().find(|entry| entry.block_id == block_id) {
+            entry.referenced = true;
+            Arc::clone(&entry.cache)
         } else {
             // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+            if self.queue.len() >= self.capacity && !self.evict_one() {
+                // Every cached block is genuinely pinned right now: grow
+                // instead of panicking, rather than refuse to cache a block
+                // that is actually needed.
+                self.capacity += 1;
             }
             // load block into mem and push back
             let block_cache = Arc::new(Mutex::new(BlockCache::new(
                 block_id,
                 Arc::clone(&block_device),
+                self.checksum_region_start,
             )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
+            self.queue.push_back(CacheEntry {
+                block_id,
+                cache: Arc::clone(&block_cache),
+                referenced: true,
+            });
             block_cache
         }
     }
+    /// Try to evict one unpinned, not-recently-referenced entry using the
+    /// clock/second-chance policy. Returns `true` if an entry was evicted.
+    fn evict_one(&mut self) -> bool {
+        if self.queue.is_empty() {
+            return false;
+        }
+        // Two full sweeps is always enough: the first clears every
+        // remaining reference bit, the second finds an unreferenced,
+        // unpinned victim if one exists.
+        for _ in 0..2 * self.queue.len() {
+            if self.hand >= self.queue.len() {
+                self.hand = 0;
+            }
+            let pinned = Arc::strong_count(&self.queue[self.hand].cache) > 1;
+            if pinned {
+                self.hand = (self.hand + 1) % self.queue.len();
+                continue;
+            }
+            if self.queue[self.hand].referenced {
+                self.queue[self.hand].referenced = false;
+                self.hand = (self.hand + 1) % self.queue.len();
+                continue;
+            }
+            self.queue.remove(self.hand);
+            return true;
+        }
+        false
+    }
+    /// Read every cached block and its persisted checksum, returning the
+    /// block ids whose checksum no longer matches so callers can detect
+    /// silent corruption from a flaky `BlockDevice`.
+    pub fn scrub(&self) -> Vec<usize> {
+        self.queue
+            .iter()
+            .filter(|entry| !entry.cache.lock().verify())
+            .map(|entry| entry.block_id)
+            .collect()
+    }
 }
 
 lazy_static! {
-    /// BLOCK_CACHE_MANAGER: Glocal instance of BlockCacheManager.
-    pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
-        Mutex::new(BlockCacheManager::new());
+    /// Global instance of BlockCacheManager.
+    ///
+    /// Constructed with an implausible placeholder `checksum_region_start`
+    /// (see `UNINIT_CHECKSUM_REGION_START`) since this global is built
+    /// before any particular device/layout is known. Whoever mounts or
+    /// creates the filesystem must call [`init_checksum_region_start`] with
+    /// a value sized from the real on-disk layout (e.g. the device's total
+    /// block count, so the checksum region sits right after the data
+    /// region) before touching a single block through this manager.
+    pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> = Mutex::new(
+        BlockCacheManager {
+            queue: VecDeque::new(),
+            capacity: BLOCK_CACHE_SIZE,
+            hand: 0,
+            dirty: BTreeMap::new(),
+            tick: 0,
+            checksum_region_start: UNINIT_CHECKSUM_REGION_START,
+            checksum_region_initialized: false,
+        }
+    );
+}
+/// Size the checksum region `BLOCK_CACHE_MANAGER` reserves from the real
+/// filesystem layout. Must be called once, before the first block is ever
+/// loaded through `get_block_cache` (typically right after `EasyFileSystem`
+/// reads the device's total block count from its super block), or every
+/// access will trip the `debug_assert` in `BlockCacheManager::get_block_cache`.
+pub fn init_checksum_region_start(checksum_region_start: usize) {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .set_checksum_region_start(checksum_region_start);
 }
 /// Get a block cache from the queue. according to the block_id.
 pub fn get_block_cache(
@@ -142,7 +422,45 @@ pub fn get_block_cache(
 /// Sync(write) all the block cache to disk.
 pub fn block_cache_sync_all() {
     let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    for entry in manager.queue.iter() {
+        entry.cache.lock().sync();
+    }
+}
+/// Sync only the dirty blocks, then clear the dirty list.
+///
+/// Takes the manager's lock just long enough to collect the dirty blocks'
+/// cache handles, then releases it before actually syncing any of them —
+/// syncing takes each block's own lock, and holding the manager's lock for
+/// that too would invert the lock order `BlockCache::get_mut` relies on.
+pub fn flush_dirty() {
+    let dirty = BLOCK_CACHE_MANAGER.lock().take_all_dirty_caches();
+    for cache in dirty {
         cache.lock().sync();
     }
 }
+/// Sync a single block immediately, regardless of how long it has been
+/// dirty, with the same manager-lock-released-before-syncing care as
+/// `flush_dirty`.
+pub fn sync_block(block_id: usize) {
+    if let Some(cache) = BLOCK_CACHE_MANAGER.lock().take_dirty_cache(block_id) {
+        cache.lock().sync();
+    }
+}
+/// Advance the write-back clock by one tick, flushing any block that has
+/// been dirty long enough, with the same manager-lock-released-before-
+/// syncing care as `flush_dirty`.
+///
+/// Nothing in this crate calls this on its own: the kernel's trap/timer-
+/// interrupt handler (outside this crate) needs to call it once per tick
+/// for the bounded crash window this is meant to provide to actually apply.
+pub fn on_timer_tick() {
+    let stale = BLOCK_CACHE_MANAGER.lock().take_stale_dirty_caches();
+    for cache in stale {
+        cache.lock().sync();
+    }
+}
+/// Read every cached block and report the ids whose checksum mismatches,
+/// i.e. possible silent corruption from the backing device.
+pub fn scrub() -> Vec<usize> {
+    BLOCK_CACHE_MANAGER.lock().scrub()
+}
@@ -1,10 +1,38 @@
 //! Process management syscalls
 use crate::{
     config::{CLOCK_FREQ, MAX_SYSCALL_NUM},
-    task::{exit_current_and_run_next, get_current_task, suspend_current_and_run_next, TaskStatus},
+    mm::{current_user_token, translated_refmut, translated_str},
+    syscall::{
+        SYSCALL_FORK, SYSCALL_GET_TIME, SYSCALL_SET_PRIORITY, SYSCALL_TASK_INFO,
+        SYSCALL_WAITPID, SYSCALL_YIELD,
+    },
+    task::{
+        exec, exit_current_and_run_next, get_current_task, suspend_current_and_run_next,
+        TaskStatus,
+    },
     timer::{get_time, get_time_us, MSEC_PER_SEC},
 };
 
+/// Time a syscall body and record it against the current task.
+///
+/// Every `sys_*` handler in this file that returns to its caller (i.e.
+/// everything but the diverging `sys_exit`, and `sys_exec`, which replaces
+/// the current task's context rather than returning through the normal
+/// path) goes through this, so process-management syscalls are timed
+/// uniformly regardless of which one actually ran. `os/src/syscall/mod.rs`
+/// isn't part of this crate, so the true single choke point — wrapping
+/// `crate::syscall::syscall`'s id match itself, which would also cover
+/// syscalls implemented in other modules — has to live there instead.
+fn timed(id: usize, f: impl FnOnce() -> isize) -> isize {
+    let start = get_time_us();
+    let ret = f();
+    let elapsed = get_time_us() - start;
+    get_current_task()
+        .inner_exclusive_access()
+        .record_syscall(id, elapsed);
+    ret
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct TimeVal {
@@ -17,8 +45,8 @@ pub struct TimeVal {
 pub struct TaskInfo {
     /// Task status in it's life cycle
     status: TaskStatus,
-    /// The numbers of syscall called by task
-    syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// Per-syscall (call count, accumulated microseconds) spent handling it
+    syscall_times: [(u32, usize); MAX_SYSCALL_NUM],
     /// Total running time of task
     time: usize,
 }
@@ -26,40 +54,117 @@ pub struct TaskInfo {
 /// task exits and submit an exit code
 pub fn sys_exit(exit_code: i32) -> ! {
     trace!("[kernel] Application exited with code {}", exit_code);
-    exit_current_and_run_next();
+    exit_current_and_run_next(exit_code);
     panic!("Unreachable in sys_exit!");
 }
 
 /// current task gives up resources for other tasks
 pub fn sys_yield() -> isize {
     trace!("kernel: sys_yield");
-    suspend_current_and_run_next();
-    0
+    timed(SYSCALL_YIELD, || {
+        suspend_current_and_run_next();
+        0
+    })
 }
 
 /// get time with second and microsecond
 pub fn sys_get_time(ts: *mut TimeVal, _tz: usize) -> isize {
     trace!("kernel: sys_get_time");
-    let us = get_time_us();
-    unsafe {
-        *ts = TimeVal {
-            sec: us / 1_000_000,
-            usec: us % 1_000_000,
-        };
-    }
-    0
+    timed(SYSCALL_GET_TIME, || {
+        let us = get_time_us();
+        unsafe {
+            *ts = TimeVal {
+                sec: us / 1_000_000,
+                usec: us % 1_000_000,
+            };
+        }
+        0
+    })
+}
+
+/// change the priority of the current task for stride scheduling
+///
+/// `prio` must be at least 2; smaller values are rejected with `-1` and the
+/// priority is left unchanged. Returns the new priority on success.
+pub fn sys_set_priority(prio: isize) -> isize {
+    trace!("kernel: sys_set_priority");
+    timed(SYSCALL_SET_PRIORITY, || {
+        if prio < 2 {
+            return -1;
+        }
+        get_current_task()
+            .inner_exclusive_access()
+            .set_priority(prio as usize);
+        prio
+    })
+}
+
+/// create a new process as a copy of the current one
+///
+/// The child gets a fresh PID, its own kernel stack, and a deep copy of the
+/// parent's address space and file descriptor table (see
+/// `TaskControlBlock::fork`), and is linked into the parent's `children`.
+/// Returns the child's PID to the parent and `0` to the child.
+pub fn sys_fork() -> isize {
+    trace!("kernel: sys_fork");
+    timed(SYSCALL_FORK, || {
+        let current_task = get_current_task();
+        let new_task = current_task.fork();
+        new_task.pid.0 as isize
+    })
+}
+
+/// replace the current process image with the ELF at `path`
+///
+/// Returns `-1` if `path` cannot be loaded; otherwise does not return to the
+/// caller, since the current task's context has been replaced.
+pub fn sys_exec(path: *const u8) -> isize {
+    trace!("kernel: sys_exec");
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    exec(&path)
+}
+
+/// wait for a child to become a zombie and reap it
+///
+/// Returns `-1` if the current task has no child with the given `pid` (`-1`
+/// matches any child), `-2` if a matching child exists but hasn't exited yet,
+/// and otherwise writes the zombie's exit code through `exit_code_ptr` and
+/// returns its pid.
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
+    trace!("kernel: sys_waitpid");
+    timed(SYSCALL_WAITPID, || {
+        let task = get_current_task();
+        match task.waitpid(pid) {
+            Ok((child_pid, exit_code)) => {
+                let token = current_user_token();
+                *translated_refmut(token, exit_code_ptr) = exit_code;
+                child_pid as isize
+            }
+            Err(no_such_child) => {
+                if no_such_child {
+                    -1
+                } else {
+                    -2
+                }
+            }
+        }
+    })
 }
 
-/// YOUR JOB: Finish sys_task_info to pass testcases
+/// report the current task's status, per-syscall counts/timing, and runtime
 pub fn sys_task_info(_ti: *mut TaskInfo) -> isize {
     trace!("kernel: sys_task_info");
-    let current_task = get_current_task();
-    unsafe {
-        *_ti = TaskInfo {
-            status: current_task.task_status,
-            syscall_times: current_task.syscall_times,
-            time: (get_time() - current_task.begin_time) * MSEC_PER_SEC / CLOCK_FREQ,   // in milliseconds
-        };
-    }
-    0
+    timed(SYSCALL_TASK_INFO, || {
+        let current_task = get_current_task();
+        let inner = current_task.inner_exclusive_access();
+        unsafe {
+            *_ti = TaskInfo {
+                status: inner.task_status,
+                syscall_times: inner.syscall_times,
+                time: (get_time() - inner.begin_time) * MSEC_PER_SEC / CLOCK_FREQ, // in milliseconds
+            };
+        }
+        0
+    })
 }
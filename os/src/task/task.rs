@@ -1,19 +1,251 @@
 //! Types related to task management
 
 use super::TaskContext;
-use crate::config::MAX_SYSCALL_NUM;
+use crate::config::{KERNEL_STACK_SIZE, MAX_SYSCALL_NUM, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT};
+use crate::fs::File;
+use crate::mm::{MapPermission, MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
+use crate::trap::TrapContext;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use lazy_static::*;
+use spin::{Mutex, MutexGuard};
 
 /// The task control block (TCB) of a task.
-#[derive(Copy, Clone)]
+///
+/// A task is now a full process: it owns a PID, knows its parent and
+/// children, and keeps its exit code around until it is reaped. Everything
+/// but the PID and kernel stack can change over the task's lifetime and is
+/// hidden behind `inner`, since a task is shared (as `Arc<TaskControlBlock>`)
+/// between its own execution, its parent's `children`, and the scheduler's
+/// ready queue.
 pub struct TaskControlBlock {
+    /// Immutable process identifier, recycled once this TCB is dropped.
+    pub pid: PidHandle,
+    /// This task's own kernel stack, mapped in `KERNEL_SPACE` at a
+    /// pid-indexed slot so it never overlaps another task's.
+    pub kstack: KernelStack,
+    /// Everything about a task that can change after creation.
+    inner: Mutex<TaskControlBlockInner>,
+}
+
+/// The mutable part of a [`TaskControlBlock`].
+pub struct TaskControlBlockInner {
     /// The task status in it's lifecycle
     pub task_status: TaskStatus,
     /// The task context
     pub task_cx: TaskContext,
-    /// The record of syscall times
-    pub syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// This task's address space.
+    pub memory_set: MemorySet,
+    /// The physical page this task's `TrapContext` lives on within its own
+    /// `memory_set`.
+    pub trap_cx_ppn: PhysPageNum,
+    /// Open file descriptors; a slot is `None` once closed.
+    pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    /// Per-syscall (call count, accumulated microseconds) spent handling it
+    pub syscall_times: [(u32, usize); MAX_SYSCALL_NUM],
     /// The begin time of task in ticks
     pub begin_time: usize,
+    /// Priority used for stride scheduling; a task is scheduled proportionally
+    /// more often the higher this is. Must be at least 2.
+    pub priority: usize,
+    /// Pass added to `stride` the last time this task was scheduled,
+    /// `BIG_STRIDE / priority`.
+    pub pass: usize,
+    /// Current stride value; the scheduler always picks the ready task with
+    /// the smallest stride.
+    pub stride: usize,
+    /// The parent process, if any. Only the initial process has no parent.
+    pub parent: Option<Weak<TaskControlBlock>>,
+    /// Child processes, in the order they were forked.
+    pub children: Vec<Arc<TaskControlBlock>>,
+    /// Exit code submitted through `sys_exit`; meaningful once the task has
+    /// become a zombie waiting to be reaped by `sys_waitpid`.
+    pub exit_code: i32,
+}
+
+/// The big stride constant used for stride scheduling: each time a task runs
+/// its stride advances by `BIG_STRIDE / priority`.
+pub const BIG_STRIDE: usize = 0xFFFF;
+
+/// The priority newly created tasks start out with.
+pub const DEFAULT_PRIORITY: usize = 16;
+
+impl TaskControlBlockInner {
+    /// Advance this task's stride by its pass value, as done every time the
+    /// scheduler picks it to run.
+    pub fn advance_stride(&mut self) {
+        self.pass = BIG_STRIDE / self.priority;
+        self.stride = self.stride.wrapping_add(self.pass);
+    }
+
+    /// Returns `true` if `self` should be scheduled before `other`.
+    ///
+    /// Strides wrap around, so the comparison is done on the wrapping
+    /// difference reinterpreted as signed: this is safe as long as no single
+    /// pass exceeds `BIG_STRIDE / 2`.
+    pub fn stride_lt(&self, other: &Self) -> bool {
+        (self.stride.wrapping_sub(other.stride) as isize) <= 0
+    }
+
+    /// Update this task's priority, used by `sys_set_priority`.
+    ///
+    /// Callers must reject `prio < 2` themselves; this simply stores it.
+    pub fn set_priority(&mut self, prio: usize) {
+        self.priority = prio;
+    }
+
+    /// Record one invocation of syscall `id` that took `elapsed_us`
+    /// microseconds, called by `timed` in `syscall/process.rs` once a
+    /// syscall handler's body has run.
+    pub fn record_syscall(&mut self, id: usize, elapsed_us: usize) {
+        let slot = &mut self.syscall_times[id];
+        slot.0 += 1;
+        slot.1 += elapsed_us;
+    }
+
+    /// This task's `TrapContext`, found via `trap_cx_ppn` in its own address
+    /// space rather than the kernel's, since each task's trap context lives
+    /// at the fixed `TRAP_CONTEXT` page of its own `memory_set`.
+    pub fn trap_cx(&self) -> &'static mut TrapContext {
+        self.trap_cx_ppn.get_mut()
+    }
+}
+
+impl TaskControlBlock {
+    /// Lock and expose the mutable part of this task, following the same
+    /// exclusive-access pattern used for the other kernel-wide singletons.
+    pub fn inner_exclusive_access(&self) -> MutexGuard<'_, TaskControlBlockInner> {
+        self.inner.lock()
+    }
+
+    /// Create a new process that is a deep copy of `self`, as `sys_fork`
+    /// needs: its own copy of the address space and file descriptor table,
+    /// its own kernel stack, and a fresh pid with its syscall counts and
+    /// stride reset (`priority` and `begin_time` are copied from the parent).
+    /// The child is linked into `self.children`.
+    ///
+    /// The child's `task_cx` is *not* copied from the parent's — that field
+    /// is the parent's own kernel-context snapshot and is only meaningful
+    /// the next time the parent itself is switched out. Instead the child
+    /// gets a fresh context pointed at `trap_return` on its own kernel
+    /// stack, exactly as if it were about to return from a trap for the
+    /// first time.
+    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
+        let mut parent_inner = self.inner_exclusive_access();
+        let memory_set = MemorySet::from_existing_user(&parent_inner.memory_set);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+        let fd_table = parent_inner
+            .fd_table
+            .iter()
+            .map(|fd| fd.clone())
+            .collect();
+        let pid = pid_alloc();
+        let kstack = KernelStack::new(&pid);
+        let kstack_top = kstack.get_top();
+        let child = Arc::new(TaskControlBlock {
+            pid,
+            kstack,
+            inner: Mutex::new(TaskControlBlockInner {
+                task_status: TaskStatus::Ready,
+                task_cx: TaskContext::goto_trap_return(kstack_top),
+                memory_set,
+                trap_cx_ppn,
+                fd_table,
+                syscall_times: [(0, 0); MAX_SYSCALL_NUM],
+                begin_time: parent_inner.begin_time,
+                priority: parent_inner.priority,
+                pass: 0,
+                stride: 0,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+            }),
+        });
+        parent_inner.children.push(Arc::clone(&child));
+        // The child's trap context was copied byte-for-byte from the
+        // parent's own address space along with the rest of `memory_set`;
+        // it only needs to be repointed at its own kernel stack and told
+        // to return 0 from `fork` instead of the parent's pid.
+        let trap_cx = child.inner_exclusive_access().trap_cx();
+        trap_cx.kernel_sp = kstack_top;
+        trap_cx.x[10] = 0;
+        child
+    }
+
+    /// Reap the zombie child with the given `pid`, or any zombie child if
+    /// `pid` is `-1`, returning its pid and exit code.
+    ///
+    /// Returns `Err(true)` if `self` has no child matching `pid` at all, and
+    /// `Err(false)` if a matching child exists but hasn't exited yet.
+    pub fn waitpid(self: &Arc<Self>, pid: isize) -> Result<(usize, i32), bool> {
+        let mut inner = self.inner_exclusive_access();
+        let matches = |child: &Arc<TaskControlBlock>| pid == -1 || child.pid.0 == pid as usize;
+        if !inner.children.iter().any(matches) {
+            return Err(true);
+        }
+        let zombie_index = inner.children.iter().position(|child| {
+            matches(child) && child.inner_exclusive_access().task_status == TaskStatus::Exited
+        });
+        match zombie_index {
+            Some(index) => {
+                let child = inner.children.remove(index);
+                // No other `Arc` should outlive the parent's own reference
+                // once a zombie is reaped, so this is the last owner.
+                let exit_code = child.inner_exclusive_access().exit_code;
+                Ok((child.pid.0, exit_code))
+            }
+            None => Err(false),
+        }
+    }
+
+    /// Move every surviving child onto `init`'s `children`, called from
+    /// `exit_current_and_run_next` once `self` becomes a zombie so no
+    /// process is ever left parentless.
+    pub fn reparent_children_to(self: &Arc<Self>, init: &Arc<TaskControlBlock>) {
+        let mut inner = self.inner_exclusive_access();
+        let mut init_inner = init.inner_exclusive_access();
+        for child in inner.children.drain(..) {
+            child.inner_exclusive_access().parent = Some(Arc::downgrade(init));
+            init_inner.children.push(child);
+        }
+    }
+}
+
+/// Pick the ready task with the smallest stride from `ready_tasks`, and
+/// advance its stride as it is about to run.
+///
+/// This is the stride-scheduling core of the task manager's "fetch next"
+/// step: call it with the current ready queue in place of a plain
+/// front-of-queue pop.
+pub fn fetch_next<'a, I>(ready_tasks: I) -> Option<&'a Arc<TaskControlBlock>>
+where
+    I: IntoIterator<Item = &'a Arc<TaskControlBlock>>,
+{
+    let mut best: Option<&'a Arc<TaskControlBlock>> = None;
+    for task in ready_tasks {
+        best = match best {
+            None => Some(task),
+            Some(current_best) => {
+                let task_inner = task.inner_exclusive_access();
+                let best_inner = current_best.inner_exclusive_access();
+                let task_is_smaller = task_inner.stride_lt(&best_inner);
+                drop(task_inner);
+                drop(best_inner);
+                if task_is_smaller {
+                    Some(task)
+                } else {
+                    Some(current_best)
+                }
+            }
+        };
+    }
+    if let Some(task) = best {
+        task.inner_exclusive_access().advance_stride();
+    }
+    best
 }
 
 /// The status of a task
@@ -28,3 +260,109 @@ pub enum TaskStatus {
     /// exited
     Exited,
 }
+
+/// A process identifier handed out by [`pid_alloc`].
+///
+/// Dropping the handle returns the id to [`PID_ALLOCATOR`] so a later process
+/// can reuse it.
+pub struct PidHandle(pub usize);
+
+impl Drop for PidHandle {
+    fn drop(&mut self) {
+        PID_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
+
+/// The `(bottom, top)` virtual address range a task's kernel stack occupies
+/// in `KERNEL_SPACE`, indexed by pid so no two tasks' stacks overlap. Stacks
+/// are laid out below the trampoline page, each followed by one unmapped
+/// guard page to turn a kernel-stack overflow into a page fault instead of
+/// silent corruption of the next task's stack.
+fn kernel_stack_position(pid: usize) -> (usize, usize) {
+    let top = TRAMPOLINE - pid * (KERNEL_STACK_SIZE + PAGE_SIZE);
+    let bottom = top - KERNEL_STACK_SIZE;
+    (bottom, top)
+}
+
+/// A task's kernel stack, mapped into `KERNEL_SPACE` for the lifetime of the
+/// owning [`TaskControlBlock`] and unmapped again when it is dropped.
+pub struct KernelStack {
+    pid: usize,
+}
+
+impl KernelStack {
+    /// Map a fresh kernel stack for `pid_handle` and return a handle to it.
+    pub fn new(pid_handle: &PidHandle) -> Self {
+        let pid = pid_handle.0;
+        let (kstack_bottom, kstack_top) = kernel_stack_position(pid);
+        KERNEL_SPACE.exclusive_access().insert_framed_area(
+            kstack_bottom.into(),
+            kstack_top.into(),
+            MapPermission::R | MapPermission::W,
+        );
+        Self { pid }
+    }
+
+    /// The virtual address this stack's top (its initial `sp`) sits at.
+    pub fn get_top(&self) -> usize {
+        let (_, kstack_top) = kernel_stack_position(self.pid);
+        kstack_top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let (kstack_bottom, _) = kernel_stack_position(self.pid);
+        let kstack_bottom_va: VirtAddr = kstack_bottom.into();
+        KERNEL_SPACE
+            .exclusive_access()
+            .remove_area_with_start_vpn(kstack_bottom_va.into());
+    }
+}
+
+/// A simple recycling allocator for process identifiers.
+struct PidAllocator {
+    current: usize,
+    recycled: Vec<usize>,
+}
+
+impl PidAllocator {
+    /// Create an empty allocator starting from pid 0.
+    pub fn new() -> Self {
+        Self {
+            current: 0,
+            recycled: Vec::new(),
+        }
+    }
+
+    /// Allocate a pid, preferring one that has been recycled.
+    pub fn alloc(&mut self) -> PidHandle {
+        if let Some(pid) = self.recycled.pop() {
+            PidHandle(pid)
+        } else {
+            self.current += 1;
+            PidHandle(self.current - 1)
+        }
+    }
+
+    /// Return `pid` to the pool once its owning task has been dropped.
+    pub fn dealloc(&mut self, pid: usize) {
+        assert!(pid < self.current);
+        assert!(
+            !self.recycled.iter().any(|recycled_pid| *recycled_pid == pid),
+            "pid {} has been deallocated twice!",
+            pid
+        );
+        self.recycled.push(pid);
+    }
+}
+
+lazy_static! {
+    /// The global pool process identifiers are allocated from.
+    static ref PID_ALLOCATOR: Mutex<PidAllocator> = Mutex::new(PidAllocator::new());
+}
+
+/// Allocate a fresh process identifier.
+pub fn pid_alloc() -> PidHandle {
+    PID_ALLOCATOR.lock().alloc()
+}